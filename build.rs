@@ -0,0 +1,7 @@
+extern crate cc;
+
+fn main() {
+    if cfg!(not(feature = "use_arch")) {
+        cc::Build::new().file("src/cpuid.c").compile("cpuid");
+    }
+}
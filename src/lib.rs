@@ -1,27 +1,18 @@
-#![feature(no_std, core, core_prelude, asm, raw)]
-#![no_std]
-
 #![crate_name = "raw_cpuid"]
 #![crate_type = "lib"]
 
-#[macro_use]
-extern crate core;
+#[cfg(feature = "serialize")]
+extern crate serde;
 
-#[cfg(test)]
+#[cfg(feature = "serialize")]
 #[macro_use]
-extern crate std;
+extern crate serde_derive;
 
-#[cfg(test)]
-#[prelude_import]
-use std::prelude::v1::*;
+use std::fmt;
+use std::slice;
+use std::str;
 
-use core::prelude::*;
-use core::iter;
-use core::raw;
-use core::str;
-use core::mem::{transmute};
-use core::fmt;
-use core::slice;
+mod native_cpuid;
 
 const MAX_ENTRIES: usize = 32;
 
@@ -36,30 +27,11 @@ macro_rules! cpuid {
 }
 
 fn cpuid2(eax: u32, ecx: u32) -> CpuIdResult {
-    let mut res = CpuIdResult{eax: 0, ebx: 0, ecx: 0, edx: 0};
-
-    unsafe {
-        asm!("movl $0, %eax" : : "r" (eax) : "eax");
-        asm!("movl $0, %ecx" : : "r" (ecx) : "ecx");
-        asm!("cpuid" : "={eax}"(res.eax) "={ebx}"(res.ebx)
-                       "={ecx}"(res.ecx) "={edx}"(res.edx)
-                     :: "eax", "ebx", "ecx", "edx");
-    }
-
-    res
+    native_cpuid::cpuid_count(eax, ecx)
 }
 
 fn cpuid1(eax: u32) -> CpuIdResult {
-    let mut res = CpuIdResult{eax: 0, ebx: 0, ecx: 0, edx: 0};
-
-    unsafe {
-        asm!("movl $0, %eax" : : "r" (eax) : "eax");
-        asm!("cpuid" : "={eax}"(res.eax) "={ebx}"(res.ebx)
-                       "={ecx}"(res.ecx) "={edx}"(res.edx)
-                     :: "eax", "ebx", "ecx", "edx");
-    }
-
-    res
+    native_cpuid::cpuid_count(eax, 0)
 }
 
 enum CpuIdLeaf {
@@ -79,6 +51,7 @@ enum CpuIdLeaf {
     ExtendedFunction,
 }
 
+#[allow(dead_code)]
 struct LeafData(CpuIdLeaf, &'static str, u32);
 
 const LEAF_INFORMATION: [LeafData; 14] = [
@@ -102,6 +75,8 @@ const LEAF_INFORMATION: [LeafData; 14] = [
 pub struct CpuId;
 
 #[derive(Debug, Copy, Clone)]
+#[repr(C)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct CpuIdResult {
     pub eax: u32,
     pub ebx: u32,
@@ -121,8 +96,38 @@ impl CpuId {
         CpuIdFeatureInfo{eax: res.eax, ebx: res.ebx, ecx: res.ecx, edx: res.edx}
     }
 
+    pub fn get_extended_function_information(&self) -> CpuIdExtendedFunctionInfo {
+        let res = cpuid!(0x80000000);
+        let mut ef = CpuIdExtendedFunctionInfo {
+            max_eax_value: res.eax,
+            data: [CpuIdResult{eax: 0, ebx: 0, ecx: 0, edx: 0}; 3],
+        };
+
+        for i in 2..5 {
+            if ef.max_eax_value >= 0x80000000 + i {
+                ef.data[(i - 2) as usize] = cpuid!(0x80000000 + i);
+            }
+        }
+
+        ef
+    }
+
+    pub fn get_extended_topology_info(&self) -> ExtendedTopologyIter {
+        ExtendedTopologyIter{level: 0}
+    }
+
+    pub fn get_cache_parameters(&self) -> CacheParametersIter {
+        CacheParametersIter{index: 0}
+    }
+
+    pub fn get_thermal_power_info(&self) -> ThermalPowerInfo {
+        let res = cpuid!(0x6);
+        ThermalPowerInfo{eax: res.eax, ebx: res.ebx, ecx: res.ecx, edx: res.edx}
+    }
+
 }
 
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct CpuIdVendorInfo {
     pub ebx: u32,
     pub ecx: u32,
@@ -145,6 +150,17 @@ impl fmt::Display for CpuIdVendorInfo {
     }
 }
 
+macro_rules! feature_flags {
+    ($reg:ident, { $($idx:expr => $name:ident),* $(,)* }) => {
+        $(
+            pub fn $name(&self) -> bool {
+                ((self.$reg >> $idx) & 0x1) != 0
+            }
+        )*
+    }
+}
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct CpuIdFeatureInfo {
     pub eax: u32,
     pub ebx: u32,
@@ -154,6 +170,31 @@ pub struct CpuIdFeatureInfo {
 
 impl CpuIdFeatureInfo {
 
+    feature_flags!(ecx, {
+        0 => sse3,
+        1 => pclmulqdq,
+        12 => fma,
+        23 => popcnt,
+        19 => sse4_1,
+        20 => sse4_2,
+        25 => aes,
+        28 => avx,
+        30 => rdrand,
+    });
+
+    feature_flags!(edx, {
+        0 => fpu,
+        4 => tsc,
+        5 => msr,
+        6 => pae,
+        7 => mce,
+        9 => apic,
+        23 => mmx,
+        25 => sse,
+        26 => sse2,
+        28 => htt,
+    });
+
     pub fn get_extended_family_id(&self) -> u8 {
         ((self.eax >> 20) & 0xff) as u8
     }
@@ -171,19 +212,427 @@ impl CpuIdFeatureInfo {
     }
 
     pub fn get_stepping_id(&self) -> u8 {
-        ((self.eax & 0b1111)) as u8
+        (self.eax & 0b1111) as u8
     }
 
     pub fn get_brand_index(&self) -> u8 {
-        (self.ebx) as u8
+        self.ebx as u8
     }
 
     pub fn get_cflush_cache_line_size(&self) -> u8 {
-        ((self.ebx >> 8)) as u8
+        (self.ebx >> 8) as u8
     }
 
     pub fn get_local_apic_id(&self) -> u8 {
-        ((self.ebx >> 24)) as u8
+        (self.ebx >> 24) as u8
+    }
+}
+
+impl fmt::Debug for CpuIdFeatureInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CpuIdFeatureInfo")
+            .field("sse3", &self.sse3())
+            .field("pclmulqdq", &self.pclmulqdq())
+            .field("fma", &self.fma())
+            .field("popcnt", &self.popcnt())
+            .field("sse4_1", &self.sse4_1())
+            .field("sse4_2", &self.sse4_2())
+            .field("aes", &self.aes())
+            .field("avx", &self.avx())
+            .field("rdrand", &self.rdrand())
+            .field("fpu", &self.fpu())
+            .field("tsc", &self.tsc())
+            .field("msr", &self.msr())
+            .field("pae", &self.pae())
+            .field("mce", &self.mce())
+            .field("apic", &self.apic())
+            .field("mmx", &self.mmx())
+            .field("sse", &self.sse())
+            .field("sse2", &self.sse2())
+            .field("htt", &self.htt())
+            .finish()
+    }
+}
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct CpuIdExtendedFunctionInfo {
+    max_eax_value: u32,
+    data: [CpuIdResult; 3]
+}
+
+impl CpuIdExtendedFunctionInfo {
+
+    pub fn processor_brand_string(&self) -> Option<ProcessorBrandString> {
+        if self.max_eax_value >= 0x80000004 {
+            Some(ProcessorBrandString::new(&self.data))
+        } else {
+            None
+        }
+    }
+}
+
+pub struct ProcessorBrandString {
+    bytes: [u8; 48]
+}
+
+impl ProcessorBrandString {
+
+    fn new(data: &[CpuIdResult; 3]) -> ProcessorBrandString {
+        let mut bytes: [u8; 48] = [0; 48];
+        let mut offset = 0;
+        for res in data.iter() {
+            for reg in [res.eax, res.ebx, res.ecx, res.edx].iter() {
+                for b in as_bytes(reg) {
+                    bytes[offset] = *b;
+                    offset += 1;
+                }
+            }
+        }
+
+        ProcessorBrandString{bytes}
+    }
+
+    pub fn as_str(&self) -> &str {
+        let mut len = self.bytes.len();
+        while len > 0 && (self.bytes[len - 1] == 0 || self.bytes[len - 1] == b' ') {
+            len -= 1;
+        }
+
+        unsafe { str::from_utf8_unchecked(&self.bytes[0..len]) }
+    }
+}
+
+impl fmt::Display for ProcessorBrandString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// `derive(Serialize, Deserialize)` only supports arrays up to 32 elements,
+// but the brand string is a fixed 48 bytes, so round-trip it as a string.
+#[cfg(feature = "serialize")]
+impl ::serde::Serialize for ProcessorBrandString {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: ::serde::Serializer
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serialize")]
+impl<'de> ::serde::Deserialize<'de> for ProcessorBrandString {
+    fn deserialize<D>(deserializer: D) -> Result<ProcessorBrandString, D::Error>
+        where D: ::serde::Deserializer<'de>
+    {
+        let s = String::deserialize(deserializer)?;
+        let mut bytes = [0u8; 48];
+        let src = s.as_bytes();
+        let len = if src.len() < bytes.len() { src.len() } else { bytes.len() };
+        bytes[..len].copy_from_slice(&src[..len]);
+
+        Ok(ProcessorBrandString{bytes})
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum TopologyType {
+    Invalid = 0,
+    SMT = 1,
+    Core = 2,
+}
+
+pub struct ExtendedTopologyIter {
+    level: u32
+}
+
+impl Iterator for ExtendedTopologyIter {
+    type Item = ExtendedTopologyLevel;
+
+    fn next(&mut self) -> Option<ExtendedTopologyLevel> {
+        let res = cpuid!(0xB, self.level);
+        self.level += 1;
+
+        if res.eax == 0 && res.ebx == 0 {
+            None
+        } else {
+            Some(ExtendedTopologyLevel{eax: res.eax, ebx: res.ebx, ecx: res.ecx, edx: res.edx})
+        }
+    }
+}
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct ExtendedTopologyLevel {
+    eax: u32,
+    ebx: u32,
+    ecx: u32,
+    edx: u32
+}
+
+impl ExtendedTopologyLevel {
+
+    pub fn level_number(&self) -> u8 {
+        (self.ecx & 0xff) as u8
+    }
+
+    pub fn level_type(&self) -> TopologyType {
+        match (self.ecx >> 8) & 0xff {
+            1 => TopologyType::SMT,
+            2 => TopologyType::Core,
+            _ => TopologyType::Invalid,
+        }
+    }
+
+    pub fn shift_right_for_next_apic_id(&self) -> u32 {
+        self.eax & 0x1f
+    }
+
+    pub fn processors(&self) -> u16 {
+        (self.ebx & 0xffff) as u16
+    }
+
+    pub fn x2apic_id(&self) -> u32 {
+        self.edx
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum CacheType {
+    Null = 0,
+    Data = 1,
+    Instruction = 2,
+    Unified = 3,
+    Reserved,
+}
+
+pub struct CacheParametersIter {
+    index: u32
+}
+
+impl Iterator for CacheParametersIter {
+    type Item = CacheParameter;
+
+    fn next(&mut self) -> Option<CacheParameter> {
+        let res = cpuid!(0x4, self.index);
+        self.index += 1;
+
+        if res.eax & 0x1f == 0 {
+            None
+        } else {
+            Some(CacheParameter{eax: res.eax, ebx: res.ebx, ecx: res.ecx, edx: res.edx})
+        }
+    }
+}
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct CacheParameter {
+    eax: u32,
+    ebx: u32,
+    ecx: u32,
+    edx: u32
+}
+
+impl CacheParameter {
+
+    pub fn cache_type(&self) -> CacheType {
+        match self.eax & 0x1f {
+            0 => CacheType::Null,
+            1 => CacheType::Data,
+            2 => CacheType::Instruction,
+            3 => CacheType::Unified,
+            _ => CacheType::Reserved,
+        }
+    }
+
+    pub fn level(&self) -> u8 {
+        ((self.eax >> 5) & 0x7) as u8
+    }
+
+    pub fn is_self_initializing(&self) -> bool {
+        ((self.eax >> 8) & 0x1) != 0
+    }
+
+    pub fn is_fully_associative(&self) -> bool {
+        ((self.eax >> 9) & 0x1) != 0
+    }
+
+    pub fn max_logical_processors_sharing(&self) -> u16 {
+        (((self.eax >> 14) & 0xfff) + 1) as u16
+    }
+
+    pub fn max_cores_for_package(&self) -> u8 {
+        (((self.eax >> 26) & 0x3f) + 1) as u8
+    }
+
+    pub fn line_size(&self) -> u16 {
+        ((self.ebx & 0xfff) + 1) as u16
+    }
+
+    pub fn physical_line_partitions(&self) -> u16 {
+        (((self.ebx >> 12) & 0x3ff) + 1) as u16
+    }
+
+    pub fn associativity(&self) -> u16 {
+        (((self.ebx >> 22) & 0x3ff) + 1) as u16
+    }
+
+    pub fn sets(&self) -> u32 {
+        self.ecx + 1
+    }
+
+    pub fn is_write_back_invalidate(&self) -> bool {
+        (self.edx & 0x1) != 0
+    }
+
+    pub fn is_inclusive(&self) -> bool {
+        ((self.edx >> 1) & 0x1) != 0
+    }
+
+    pub fn has_complex_indexing(&self) -> bool {
+        ((self.edx >> 2) & 0x1) != 0
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.associativity() as usize *
+            self.physical_line_partitions() as usize *
+            self.line_size() as usize *
+            self.sets() as usize
+    }
+}
+
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct ThermalPowerInfo {
+    eax: u32,
+    ebx: u32,
+    ecx: u32,
+    #[allow(dead_code)] // EDX is reserved on this leaf; kept for symmetry with the raw register layout
+    edx: u32
+}
+
+impl ThermalPowerInfo {
+
+    feature_flags!(eax, {
+        0 => dts,
+        1 => turbo_boost,
+        2 => arat,
+        4 => pln,
+        5 => ecmd,
+        6 => ptm,
+        7 => hwp,
+        8 => hwp_notification,
+        9 => hwp_activity_window,
+        10 => hwp_energy_performance_preference,
+        11 => hwp_package_level_request,
+        13 => hdc,
+    });
+
+    feature_flags!(ecx, {
+        0 => hw_coord_feedback,
+        3 => energy_bias_preference,
+    });
+
+    pub fn dts_irq_threshold(&self) -> u8 {
+        (self.ebx & 0xf) as u8
+    }
+}
+
+/// A snapshot of CPUID leaves, for templating a guest-visible CPUID.
+///
+/// Holds at most `MAX_ENTRIES` entries (matching the arity serde's array
+/// impls support), recorded as `(leaf, subleaf, CpuIdResult)` triples so the
+/// whole image can be round-tripped through JSON with the `serialize`
+/// feature, edited, and fed back to a VMM.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct RawCpuid {
+    entries: [(u32, u32, CpuIdResult); MAX_ENTRIES],
+    len: usize
+}
+
+impl RawCpuid {
+
+    /// Snapshot every leaf (and, where the leaf is sub-leaf indexed, every
+    /// sub-leaf) this crate knows how to decode from the running CPU.
+    ///
+    /// At most `MAX_ENTRIES` entries are kept; a CPU that exposes more
+    /// leaves/sub-leaves than that has the remainder silently dropped in
+    /// release builds (debug builds panic via `debug_assert!` instead, so
+    /// truncation is caught long before a snapshot reaches a VMM).
+    pub fn gather() -> RawCpuid {
+        let mut entries = [(0, 0, CpuIdResult{eax: 0, ebx: 0, ecx: 0, edx: 0}); MAX_ENTRIES];
+        let mut len = 0;
+
+        for leaf in LEAF_INFORMATION.iter() {
+            if len >= MAX_ENTRIES {
+                debug_assert!(false, "RawCpuid::gather: snapshot truncated, more leaves than MAX_ENTRIES ({}) were available", MAX_ENTRIES);
+                break;
+            }
+
+            match leaf.0 {
+                CpuIdLeaf::CacheParameters => {
+                    for index in 0u32.. {
+                        if len >= MAX_ENTRIES {
+                            debug_assert!(false, "RawCpuid::gather: snapshot truncated, more cache parameter entries than MAX_ENTRIES ({}) were available", MAX_ENTRIES);
+                            break;
+                        }
+                        let res = cpuid!(leaf.2, index);
+                        if res.eax & 0x1f == 0 {
+                            break;
+                        }
+                        entries[len] = (leaf.2, index, res);
+                        len += 1;
+                    }
+                },
+                CpuIdLeaf::ExtendedTopology => {
+                    for level in 0u32.. {
+                        if len >= MAX_ENTRIES {
+                            debug_assert!(false, "RawCpuid::gather: snapshot truncated, more extended topology levels than MAX_ENTRIES ({}) were available", MAX_ENTRIES);
+                            break;
+                        }
+                        let res = cpuid!(leaf.2, level);
+                        if res.eax == 0 && res.ebx == 0 {
+                            break;
+                        }
+                        entries[len] = (leaf.2, level, res);
+                        len += 1;
+                    }
+                },
+                CpuIdLeaf::ExtendedFunction => {
+                    let res = cpuid!(leaf.2);
+                    entries[len] = (leaf.2, 0, res);
+                    len += 1;
+
+                    for sub in 2..5 {
+                        if len >= MAX_ENTRIES {
+                            debug_assert!(false, "RawCpuid::gather: snapshot truncated, more extended function leaves than MAX_ENTRIES ({}) were available", MAX_ENTRIES);
+                            break;
+                        }
+                        if res.eax >= 0x80000000 + sub {
+                            entries[len] = (0x80000000 + sub, 0, cpuid!(0x80000000 + sub));
+                            len += 1;
+                        }
+                    }
+                },
+                _ => {
+                    entries[len] = (leaf.2, 0, cpuid!(leaf.2));
+                    len += 1;
+                },
+            }
+        }
+
+        RawCpuid{entries, len}
+    }
+
+    /// Overwrite `other`'s entries with this snapshot's, leaf-for-leaf.
+    pub fn apply_to(&self, other: &mut RawCpuid) {
+        for i in 0..self.len {
+            other.entries[i] = self.entries[i];
+        }
+        other.len = self.len;
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, (u32, u32, CpuIdResult)> {
+        self.entries[0..self.len].iter()
     }
 }
 
@@ -214,4 +663,74 @@ fn feature_info() {
     println!("{}", finfo.get_brand_index());
     println!("{}", finfo.get_cflush_cache_line_size());
     println!("{}", finfo.get_local_apic_id());
+    println!("{:#?}", finfo);
+}
+
+#[cfg(test)]
+#[test]
+fn brand_string() {
+    let cpu: CpuId = CpuId;
+    let exinfo = cpu.get_extended_function_information();
+
+    match exinfo.processor_brand_string() {
+        Some(bstr) => println!("{}", bstr),
+        None => println!("brand string not supported"),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn extended_topology_info() {
+    let cpu: CpuId = CpuId;
+
+    for level in cpu.get_extended_topology_info() {
+        println!("{:?} level={} processors={} x2apic_id={}",
+                 level.level_type(), level.level_number(), level.processors(), level.x2apic_id());
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn cache_parameters() {
+    let cpu: CpuId = CpuId;
+
+    for cache in cpu.get_cache_parameters() {
+        println!("{:?} level={} size={}", cache.cache_type(), cache.level(), cache.size_in_bytes());
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn raw_cpuid_gather_and_apply() {
+    let snapshot = RawCpuid::gather();
+
+    // Start from an empty image so the assertions below only pass if
+    // `apply_to` actually copied the register contents over.
+    let mut other = RawCpuid{
+        entries: [(0, 0, CpuIdResult{eax: 0, ebx: 0, ecx: 0, edx: 0}); MAX_ENTRIES],
+        len: 0,
+    };
+
+    snapshot.apply_to(&mut other);
+
+    assert!(other.len == snapshot.len);
+    for (a, b) in snapshot.iter().zip(other.iter()) {
+        assert!(a.0 == b.0);
+        assert!(a.1 == b.1);
+        assert!(a.2.eax == b.2.eax);
+        assert!(a.2.ebx == b.2.ebx);
+        assert!(a.2.ecx == b.2.ecx);
+        assert!(a.2.edx == b.2.edx);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn thermal_power_info() {
+    let cpu: CpuId = CpuId;
+    let tpinfo = cpu.get_thermal_power_info();
+
+    println!("{}", tpinfo.turbo_boost());
+    println!("{}", tpinfo.hwp());
+    println!("{}", tpinfo.dts_irq_threshold());
 }
\ No newline at end of file
@@ -0,0 +1,42 @@
+//! Backends that perform the actual `cpuid` instruction.
+//!
+//! Selecting the `use_arch` Cargo feature routes through the stable
+//! `std::arch` intrinsics. Without it we fall back to a tiny C shim so the
+//! crate still builds on toolchains whose `std::arch` support lags behind.
+
+use super::CpuIdResult;
+
+#[cfg(feature = "use_arch")]
+pub fn cpuid_count(eax: u32, ecx: u32) -> CpuIdResult {
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::__cpuid_count;
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::__cpuid_count;
+
+    let res = __cpuid_count(eax, ecx);
+    CpuIdResult { eax: res.eax, ebx: res.ebx, ecx: res.ecx, edx: res.edx }
+}
+
+#[cfg(not(feature = "use_arch"))]
+pub fn cpuid_count(eax: u32, ecx: u32) -> CpuIdResult {
+    extern "C" {
+        fn cpuid(eax: u32, ecx: u32, out: *mut u32);
+    }
+
+    let mut out: [u32; 4] = [0, 0, 0, 0];
+    unsafe {
+        cpuid(eax, ecx, out.as_mut_ptr());
+    }
+
+    CpuIdResult { eax: out[0], ebx: out[1], ecx: out[2], edx: out[3] }
+}
+
+#[cfg(test)]
+#[test]
+fn cpuid_count_leaf_zero() {
+    // Leaf 0 reports the highest supported standard leaf in EAX; every real
+    // CPU supports at least leaf 1, so this is a basic sanity check that the
+    // selected backend is actually wired up to the hardware.
+    let res = cpuid_count(0, 0);
+    assert!(res.eax >= 1);
+}